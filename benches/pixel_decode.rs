@@ -0,0 +1,43 @@
+// Compares the old per-pixel decode against the `chunks_exact` bulk decode
+// used by `Image::parse`, on a 256x256 cursor's worth of pixel data.
+#![feature(test)]
+
+extern crate test;
+
+use test::Bencher;
+
+const WIDTH: usize = 256;
+const HEIGHT: usize = 256;
+
+fn sample_pixel_bytes() -> Vec<u8> {
+    (0..(WIDTH * HEIGHT * 4)).map(|i| (i % 256) as u8).collect()
+}
+
+fn decode_one_pixel_at_a_time(bytes: &[u8]) -> Vec<u32> {
+    let mut pixels = Vec::with_capacity(WIDTH * HEIGHT);
+    let mut count = 0;
+    for _ in 0..(WIDTH * HEIGHT) {
+        let word = &bytes[count..count + 4];
+        pixels.push(u32::from_le_bytes([word[0], word[1], word[2], word[3]]));
+        count += 4;
+    }
+    pixels
+}
+
+fn decode_in_bulk(bytes: &[u8]) -> Vec<u32> {
+    bytes.chunks_exact(4)
+        .map(|word| u32::from_le_bytes([word[0], word[1], word[2], word[3]]))
+        .collect()
+}
+
+#[bench]
+fn bench_decode_one_pixel_at_a_time(b: &mut Bencher) {
+    let bytes = sample_pixel_bytes();
+    b.iter(|| decode_one_pixel_at_a_time(&bytes));
+}
+
+#[bench]
+fn bench_decode_in_bulk(b: &mut Bencher) {
+    let bytes = sample_pixel_bytes();
+    b.iter(|| decode_in_bulk(&bytes));
+}